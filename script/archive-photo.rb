@@ -5,19 +5,70 @@ require "exif"
 require "forwardable"
 require "json"
 require "optparse"
+require "optparse/time"
 require "pathname"
 require "pry"
 require "securerandom"
+require "set"
 
 def options
   @options ||= OpenStruct.new
 end
 
+SIZE_UNITS = {"" => 1, "K" => 1024, "M" => 1024**2, "G" => 1024**3}
+
+##
+# Accepts a number of bytes with an optional K, M, or G suffix, such as 500, 10K, or 2M.
+def parse_size(value)
+  match = value.match(/\A(\d+)([KMG]?)B?\z/i)
+  raise OptionParser::InvalidArgument, value if match.nil?
+
+  match[1].to_i * SIZE_UNITS.fetch(match[2].upcase)
+end
+
 ARGV.options do |opts|
   opts.on("-a", "--archive=", String) { |val| options.archive = val }
   opts.on("-x", "--extension=", String) { |val| options.extension = val }
   opts.on("-R", "--recursive") { |val| options.recursive = true }
+  opts.on("--max-depth=", Integer, "With -R, descend at most this many directory levels; 0 stays in the top one.") do |val|
+    raise OptionParser::InvalidArgument, val.to_s if val.negative?
+
+    options.max_depth = val
+  end
   opts.on("-i", "--info") { |val| options.info = true }
+  opts.on("--min-size=", String, "Skip files smaller than this size, e.g. 10K.") { |val| options.min_size = parse_size(val) }
+  opts.on("--max-size=", String, "Skip files larger than this size, e.g. 2G.") { |val| options.max_size = parse_size(val) }
+  opts.on("--skip-errors", "Report files that can't be moved and carry on with the rest. Files with no date are always skipped.") { |val| options.skip_errors = true }
+  opts.on("--depth=", Integer, "Date folders to create: 1 for YYYY, 2 for YYYY/MM, or 3 for YYYY/MM/DD (default).") do |val|
+    raise OptionParser::InvalidArgument, val.to_s unless (1..3).cover?(val)
+
+    options.depth = val
+  end
+  opts.on("--time-source=", %w[created modified], "File time to use when there's no EXIF date: created (default) or modified.") do |val|
+    options.time_source = val
+  end
+  opts.on("--min-year=", Integer, "Ignore EXIF dates before this year and use the file time instead.") do |val|
+    options.min_year = val
+  end
+  opts.on("--prune-empty-dirs", "Remove directories left empty after their files are moved.") do |val|
+    options.prune_empty_dirs = true
+  end
+  opts.on("--exclude-hidden", "Skip files and directories whose names begin with a dot.") { |val| options.exclude_hidden = true }
+  opts.on("--after=", Time, "Only move files modified on or after this local time.") { |val| options.after = val }
+  opts.on("--before=", Time, "Only move files modified before this local time.") { |val| options.before = val }
+  opts.on("--flatten", "Move files directly into the archive without date folders.") { |val| options.flatten = true }
+  opts.on("--preserve-tree", "With -R, keep each file's subdirectories under its date folder.") do |val|
+    options.preserve_tree = true
+  end
+  opts.on("--group-sidecars", "File sidecars and RAW+JPEG pairs with the photo of the same name.") do |val|
+    options.group_sidecars = true
+  end
+  opts.on("--files-from=", String, "Read paths to archive, one per line, from a file or - for stdin.") { |val| options.files_from = val }
+  opts.on("--retries=", Integer, "Times to retry a move that fails with a transient error (default 3).") do |val|
+    raise OptionParser::InvalidArgument, val.to_s if val.negative?
+
+    options.retries = val
+  end
 
   opts.on_tail("-h", "--help") do
     puts opts
@@ -25,14 +76,34 @@ ARGV.options do |opts|
   end
 
   opts.parse!
+rescue OptionParser::ParseError => e
+  abort e.message
 end
 
 class ArchiveFile
-  attr_reader :file, :dest
+  class NoDateAvailable < StandardError; end
+  class ReadOnlySource < StandardError; end
 
-  def initialize(file:, dest:)
+  DATE_FOLDERS = %w[%Y %m %d]
+  TIME_SOURCES = {"created" => %i[birthtime mtime], "modified" => %i[mtime birthtime]}
+  TRANSIENT_ERRORS = [Errno::EINTR, Errno::EAGAIN, Errno::ETIMEDOUT]
+  SIDECAR_EXTENSIONS = %w[.xmp .aae .json]
+
+  attr_reader :file, :dest, :flatten, :depth, :time_source, :min_year, :retries, :tree, :primary
+
+  def initialize(
+    file:, dest:, flatten: false, depth: DATE_FOLDERS.count, time_source: "created", min_year: nil, retries: 3,
+    tree: nil, primary: nil
+  )
     @file = Pathname.new(file)
     @dest = dest
+    @flatten = flatten
+    @depth = depth
+    @time_source = time_source
+    @min_year = min_year
+    @retries = retries
+    @tree = tree
+    @primary = primary
   end
 
   def data
@@ -51,27 +122,115 @@ class ArchiveFile
     file.extname.downcase
   end
 
+  def size
+    file.size
+  end
+
+  def mtime
+    file.mtime
+  end
+
   ##
-  # .birthtime will raise a NotImplementedError in some filesystems, notably with Docker, so we'll default to
-  # modification time which seems to be the best fit for files exported from their original locations.
+  # A sidecar takes its photo's date and name, so the two end up side by side in the archive.
   def original_date
-    @original_date ||= begin
-      DateTime.parse(date_time_original)
-    rescue NotImplementedError
-      file.mtime
-    end
+    @original_date ||= primary ? primary.original_date : exif_date || filesystem_date
+  end
+
+  def name
+    @name ||= primary ? primary.name : SecureRandom.uuid
   end
 
+  ##
+  # Moving off another device, such as a camera card, copies the file and then removes the original. If the source is
+  # read-only, the removal fails after the copy has landed, leaving an archived file that every re-run copies again.
   def move
-    FileUtils.mkdir_p(new_path)
-    FileUtils.mv(file, new_path.join(SecureRandom.uuid + extname))
+    raise ReadOnlySource, "#{file}: source directory is read-only, so the file can't be moved" unless file.dirname.writable?
+
+    target = new_path.join(name + extname)
+    retrying { FileUtils.mkdir_p(new_path) }
+    retrying { transfer(target) }
   end
 
   private
 
+  ##
+  # Within a filesystem the move is a rename. Across devices the file is copied under a temporary name beside the target
+  # and renamed into place once the copy is complete, so an interrupted copy never leaves a partial file under the final
+  # name for a later run to mistake for the photo.
+  def transfer(target)
+    File.rename(file, target)
+  rescue Errno::EXDEV
+    partial = target.dirname.join(".#{target.basename}.part")
+    begin
+      FileUtils.cp(file, partial, preserve: true)
+      File.rename(partial, target)
+    ensure
+      FileUtils.rm_f(partial)
+    end
+    file.delete
+  end
+
+  ##
+  # Network mounts occasionally fail a single call with an error that goes away on its own. Those are tried again after
+  # a delay that doubles each time, up to the number of retries, and any other error is raised straight away.
+  def retrying
+    attempt = 0
+    begin
+      yield
+    rescue *TRANSIENT_ERRORS
+      raise if attempt >= retries
+
+      sleep(0.1 * 2**attempt)
+      attempt += 1
+      retry
+    end
+  end
+
+  def exif_date
+    date = date_time_original
+    return if date.nil?
+
+    parsed = DateTime.parse(date)
+    return parsed if plausible?(parsed)
+
+    warn "#{file}: ignoring implausible EXIF date #{parsed.strftime("%F")}"
+  rescue Date::Error
+    nil
+  end
+
+  ##
+  # Cameras with a dead clock battery stamp photos with a reset date, often the epoch, and a wrong clock can put them in
+  # the future. Either way the file time is a better guess. EXIF dates carry no zone, so "future" means after today's
+  # local date rather than after the current instant.
+  def plausible?(date)
+    return false if date.to_date > Date.today
+
+    min_year.nil? || date.year >= min_year
+  end
+
+  ##
+  # .birthtime will raise a NotImplementedError in some filesystems, notably with Docker, so we'll default to
+  # modification time which seems to be the best fit for files exported from their original locations. If neither is
+  # available, the file can't be placed in a date folder. Choosing the "modified" time source tries mtime first.
+  def filesystem_date
+    TIME_SOURCES.fetch(time_source).each do |time|
+      return file.public_send(time)
+    rescue NotImplementedError, SystemCallError
+      next
+    end
+
+    raise NoDateAvailable, "#{file}: no EXIF date, creation time, or modification time"
+  end
+
+  ##
+  # Scanners often record only when an image was digitized, so that date stands in when there's no original date. Some
+  # cameras pad dates with spaces or nulls, or write only the date, and an unknown date is all spaces and colons.
   def date_time_original
-    date, _ = data.date_time_original&.split
-    return file.birthtime if date.nil?
+    date, _ = [data.date_time_original, data.date_time_digitized]
+      .map { |tag| tag.to_s.delete("\0").strip }
+      .find { |tag| tag.match?(/\d/) }
+      &.split
+    return if date.nil?
 
     if date.match?(/\d\d\.\d\d\.\d\d\d\d/)
       date.split(".").rotate(-1).join("-")
@@ -80,11 +239,21 @@ class ArchiveFile
     end
   end
 
+  ##
+  # The tree is the file's directory relative to the source, kept beneath the date folders when it's given.
   def new_path
-    @new_path ||= dest
-      .join(original_date.strftime("%Y"))
-      .join(original_date.strftime("%m"))
-      .join(original_date.strftime("%d"))
+    @new_path ||= tree ? date_path.join(tree) : date_path
+  end
+
+  ##
+  # Files are renamed with a UUID when moved, so a flattened archive can hold everything in one directory without
+  # names colliding.
+  def date_path
+    return dest if flatten
+
+    DATE_FOLDERS
+      .first(depth)
+      .reduce(dest) { |path, format| path.join(original_date.strftime(format)) }
   end
 
   class NullData
@@ -92,6 +261,10 @@ class ArchiveFile
       ""
     end
 
+    def date_time_digitized
+      ""
+    end
+
     def to_h
       {}
     end
@@ -101,9 +274,43 @@ end
 def call(file)
   if options.info
     file.analyze
-  elsif file.extname.match?(options.extension || "jpg")
+  elsif file.extname.match?(options.extension || "jpg") && within_size?(file) && within_mtime?(file)
     file.move
+    moved_from << file.file.dirname
   end
+rescue ArchiveFile::NoDateAvailable, ArchiveFile::ReadOnlySource => e
+  failures << file
+  warn "skipping #{e.message}"
+rescue => e
+  raise unless options.skip_errors
+
+  failures << file
+  warn "failed #{file.file}: #{e.message}"
+end
+
+##
+# Compares the file's modification time directly, not the EXIF-aware date used for its folder, which suits picking up
+# only what has changed on disk since a previous import.
+def within_mtime?(file)
+  return false if options.after && file.mtime < options.after
+  return false if options.before && file.mtime >= options.before
+
+  true
+end
+
+def failures
+  @failures ||= []
+end
+
+def moved_from
+  @moved_from ||= Set.new
+end
+
+def within_size?(file)
+  return false if options.min_size && file.size < options.min_size
+  return false if options.max_size && file.size > options.max_size
+
+  true
 end
 
 def destination
@@ -114,14 +321,102 @@ def destination
   end
 end
 
-def recursive(dir)
-  dir.each_child do |path|
+def archive_file(path, tree: nil, primary: nil)
+  ArchiveFile.new(
+    file: path,
+    dest: destination,
+    flatten: options.flatten,
+    depth: options.depth || ArchiveFile::DATE_FOLDERS.count,
+    time_source: options.time_source || "created",
+    min_year: options.min_year,
+    retries: options.retries || 3,
+    tree: tree,
+    primary: primary
+  )
+end
+
+##
+# Children are sorted because directory order depends on the filesystem, and runs over the same tree should visit files
+# in the same order.
+def recursive(dir, depth = 0, root: dir)
+  children = dir.children.sort
+  tree = dir.relative_path_from(root) if options.preserve_tree
+  primaries = options.group_sidecars ? primaries(children) : {}
+  files = Hash.new do |found, path|
+    found[path] = archive_file(path, tree: tree, primary: (found[primaries[path]] if primaries.key?(path)))
+  end
+
+  children.each do |path|
+    next if options.exclude_hidden && path.basename.to_s.start_with?(".")
+
     if path.directory? && options.recursive
-      recursive(path)
+      recursive(path, depth + 1, root: root) if options.max_depth.nil? || depth < options.max_depth
     else
-      call(ArchiveFile.new(file: path, dest: destination))
+      call(files[path])
+    end
+  end
+end
+
+##
+# Maps each sidecar, such as an .xmp, to the file with the same base name in its directory. The second file of a
+# RAW+JPEG pair is treated the same way and follows whichever of the two sorts first.
+def primaries(paths)
+  paths.reject(&:directory?).group_by { |path| path.sub_ext("") }.each_value.with_object({}) do |group, found|
+    primary, *companions = group.sort_by { |path| [ArchiveFile::SIDECAR_EXTENSIONS.include?(path.extname.downcase) ? 1 : 0, path] }
+    companions.each { |companion| found[companion] = primary }
+  end
+end
+
+##
+# Removes the directories this run moved files out of once they're empty, along with any ancestors that are left empty
+# in turn, stopping at the source. Directories that were already empty, or that the run never visited because of
+# --max-depth or --exclude-hidden, are left alone, and nothing reached through a symlink is removed. A directory that
+# can't be removed is reported without stopping the rest.
+def prune_empty_dirs(root)
+  moved_from.sort_by { |dir| -dir.to_s.length }.each do |dir|
+    next if dir.ascend.take_while { |parent| parent != root }.any?(&:symlink?)
+
+    while dir != root && dir.directory? && dir.empty?
+      dir.rmdir
+      dir = dir.dirname
     end
+  rescue SystemCallError => e
+    warn "couldn't remove #{dir}: #{e.message}"
   end
 end
 
-recursive(Pathname.getwd)
+def files_from(list)
+  return read_list($stdin) if list == "-"
+  abort "#{list}: no such file" unless File.file?(list)
+
+  File.open(list) { |io| read_list(io) }
+end
+
+##
+# Paths that don't exist are reported on stderr and counted as failures, but one stale entry doesn't stop the rest of
+# the list.
+def read_list(io)
+  io.each_line(chomp: true) do |line|
+    next if line.strip.empty?
+
+    path = Pathname.new(line).expand_path
+    if path.file?
+      call(archive_file(path))
+    else
+      failures << line
+      warn "skipping #{line}: no such file"
+    end
+  end
+end
+
+if options.files_from
+  files_from(options.files_from)
+else
+  recursive(Pathname.getwd)
+  prune_empty_dirs(Pathname.getwd) if options.prune_empty_dirs && options.recursive && !options.info
+end
+
+unless failures.empty?
+  warn "#{failures.count} file(s) could not be archived"
+  exit 1
+end
@@ -24,9 +24,17 @@ ARGV.options do |opts|
 end
 
 class BagDeduper
+  class InvalidManifest < StandardError; end
+  class InvalidBag < StandardError; end
+
+  SUPPORTED_VERSIONS = %w[0.97 1.0]
+
+  BSD_LINE = /\AMD5 \((?<path>.*)\) = (?<hash>\h+)\z/
+
   attr_reader :bag, :options
 
   def initialize(path:, options:)
+    check_declaration(Pathname.new(path))
     @bag = BagIt::Bag.new(path)
     @options = options
   end
@@ -35,12 +43,12 @@ class BagDeduper
     set.each do |key, files|
       next if files.count < 2
 
-      files.shift
+      kept = files.shift
       files.map do |file|
         if options.run
           remove_file(file)
         else
-          puts "delete #{file}"
+          puts "delete #{file} (matches #{kept})"
         end
       end
     end
@@ -48,10 +56,54 @@ class BagDeduper
 
   private
 
+  ##
+  # Refuses to touch a directory that doesn't declare itself as a UTF-8 bag of a BagIt version we understand, since
+  # deleting from the wrong directory can't be undone. This has to run before BagIt::Bag.new, which writes a default
+  # bagit.txt into any directory that lacks one.
+  def check_declaration(dir)
+    declaration = dir.join("bagit.txt")
+    raise InvalidBag, "#{dir}: missing bagit.txt" unless declaration.file?
+
+    tags = declaration.each_line(chomp: true).each_with_object({}) do |line, found|
+      name, value = line.split(":", 2)
+      found[name.strip] = value.strip unless value.nil?
+    end
+
+    version = tags["BagIt-Version"]
+    raise InvalidBag, "#{declaration}: unsupported BagIt-Version #{version.inspect}" unless SUPPORTED_VERSIONS.include?(version)
+
+    encoding = tags["Tag-File-Character-Encoding"]
+    raise InvalidBag, "#{declaration}: Tag-File-Character-Encoding must be UTF-8, got #{encoding.inspect}" unless encoding&.casecmp?("UTF-8")
+  end
+
   def manifest_md5
-    @manifest_md5 ||= Pathname.new(bag.manifest_file("md5"))
-      .readlines
-      .map(&:chomp)
+    @manifest_md5 ||= parse_manifest(bag.manifest_file("md5"))
+  end
+
+  ##
+  # Reads a manifest into pairs of checksum and path. Blank lines and comments beginning with # are skipped, and any
+  # line that doesn't have both a hex checksum and a path raises an error naming the file and line number. Everything
+  # after the checksum is the path, so paths may contain spaces, and the BagIt encodings %0A, %0D, and %25 are decoded.
+  # BSD-style MD5 lines such as "MD5 (data/a.txt) = 60b7..." are read too, and checksums are compared in lowercase.
+  #
+  # @return [Array<Array(String, String)>]
+  def parse_manifest(file)
+    Pathname.new(file).each_line.with_index(1).filter_map do |line, number|
+      next if line.strip.empty? || line.lstrip.start_with?("#")
+
+      entry = line.chomp.lstrip
+      bsd = BSD_LINE.match(entry.rstrip)
+      hash, path = bsd ? [bsd[:hash], bsd[:path]] : entry.split(/[ \t]+/, 2)
+      if path.nil? || path.strip.empty? || !hash.match?(/\A\h+\z/)
+        raise InvalidManifest, "#{file}:#{number}: expected a checksum and a path, got #{line.chomp.inspect}"
+      end
+
+      [hash.downcase, decode_path(path)]
+    end
+  end
+
+  def decode_path(path)
+    path.gsub(/%(0A|0D|25)/i) { Regexp.last_match(1).hex.chr }
   end
 
   ##
@@ -61,8 +113,7 @@ class BagDeduper
   # @return [Hash]
   def set
     {}.tap do |set|
-      manifest_md5.map do |manifest|
-        hash, path = manifest.split
+      manifest_md5.map do |hash, path|
         set.has_key?(hash) ? set[hash].push(path) : set[hash] = [path]
       end
     end
@@ -79,9 +130,7 @@ class RemoteDeduper < BagDeduper
   private
 
   def remote_manifest_md5
-    @remote_manifest_md5 ||= Pathname.new(options.remote_manifest)
-      .readlines
-      .map(&:chomp)
+    @remote_manifest_md5 ||= parse_manifest(options.remote_manifest)
   end
 
   ##
@@ -92,8 +141,7 @@ class RemoteDeduper < BagDeduper
   def set
     local = super
 
-    remote_manifest_md5.map do |remote_manifest|
-      hash, path = remote_manifest.split
+    remote_manifest_md5.map do |hash, path|
       local[hash].prepend(path) if local.has_key?(hash)
     end
 
@@ -101,8 +149,12 @@ class RemoteDeduper < BagDeduper
   end
 end
 
-if options.remote_manifest
-  RemoteDeduper.new(path: ARGV[0], options: options).call
-else
-  BagDeduper.new(path: ARGV[0], options: options).call
+begin
+  if options.remote_manifest
+    RemoteDeduper.new(path: ARGV[0], options: options).call
+  else
+    BagDeduper.new(path: ARGV[0], options: options).call
+  end
+rescue BagDeduper::InvalidBag, BagDeduper::InvalidManifest => e
+  abort e.message
 end
@@ -0,0 +1,519 @@
+require "fileutils"
+require "pathname"
+require "tmpdir"
+
+RSpec.describe "Archiving photos" do
+  let(:script) { File.expand_path("../script/archive-photo.rb", __dir__) }
+  let(:source) { Pathname.new(Dir.mktmpdir) }
+  let(:archive) { Pathname.new(Dir.mktmpdir) }
+
+  after do
+    FileUtils.rm_rf(source)
+    FileUtils.rm_rf(archive)
+  end
+
+  def archive_photo(*args, env: {})
+    IO.popen(env, ["ruby", script, "-a", archive.to_s, *args], chdir: source.to_s, err: [:child, :out], &:read)
+  end
+
+  def archived
+    archive.glob("**/*").select(&:file?)
+  end
+
+  context "when reading the list of files from a file" do
+    before do
+      source.join("a.txt").write("a")
+      source.join("b.txt").write("b")
+      source.join("list.txt").write("a.txt\nmissing.txt\n")
+    end
+
+    it "archives only the listed files and reports the missing ones" do
+      output = archive_photo("-x", "txt", "--files-from=list.txt")
+      expect(output).to eq("skipping missing.txt: no such file\n1 file(s) could not be archived\n")
+      expect($?.exitstatus).to eq(1)
+      expect(archived.count).to eq(1)
+      expect(source.join("a.txt")).not_to exist
+      expect(source.join("b.txt")).to exist
+    end
+
+    it "stops if the list itself is missing" do
+      expect(archive_photo("-x", "txt", "--files-from=other.txt")).to eq("other.txt: no such file\n")
+      expect($?.exitstatus).to eq(1)
+      expect(archived).to be_empty
+    end
+  end
+
+  context "when flattening the archive" do
+    before do
+      source.join("a.txt").write("a")
+      source.join("b.txt").write("b")
+    end
+
+    it "moves every file directly into the archive" do
+      archive_photo("-x", "txt", "--flatten")
+      expect(archive.children).to all(be_file)
+      expect(archive.children.count).to eq(2)
+    end
+  end
+
+  context "when preserving the source tree" do
+    before do
+      source.join("trip", "day-1").mkpath
+      source.join("a.txt").write("a")
+      source.join("trip", "day-1", "b.txt").write("b")
+      [source.join("a.txt"), source.join("trip", "day-1", "b.txt")].each do |path|
+        File.utime(Time.new(2020, 12, 26, 12), Time.new(2020, 12, 26, 12), path)
+      end
+    end
+
+    it "reproduces the subdirectories under the date folder" do
+      archive_photo("-R", "-x", "txt", "--time-source=modified", "--preserve-tree")
+      expect(archived.map(&:dirname)).to contain_exactly(
+        archive.join("2020", "12", "26"),
+        archive.join("2020", "12", "26", "trip", "day-1")
+      )
+    end
+  end
+
+  context "when grouping sidecars" do
+    before do
+      FileUtils.cp("fixtures/photos/digitized-only.jpg", source.join("a.jpg"))
+      source.join("a.xmp").write("<x:xmpmeta/>")
+      File.utime(Time.new(2018, 1, 1, 12), Time.new(2018, 1, 1, 12), source.join("a.xmp"))
+    end
+
+    it "files the sidecar with its photo under the same name" do
+      archive_photo("-x", "jpg|xmp", "--group-sidecars")
+      expect(archived.map(&:dirname)).to all(eq(archive.join("2020", "12", "26")))
+      expect(archived.map(&:extname)).to contain_exactly(".jpg", ".xmp")
+      expect(archived.map { |path| path.basename(path.extname) }.uniq.count).to eq(1)
+    end
+  end
+
+  context "when limiting files by size" do
+    before do
+      source.join("small.txt").write("a" * 1023)
+      source.join("lower.txt").write("a" * 1024)
+      source.join("upper.txt").write("a" * 4096)
+      source.join("large.txt").write("a" * 4097)
+    end
+
+    it "moves only the files within the range" do
+      archive_photo("-x", "txt", "--min-size=1K", "--max-size=4K")
+      expect(archived.map(&:size)).to contain_exactly(1024, 4096)
+      expect(source.children.map(&:basename).map(&:to_s)).to contain_exactly("small.txt", "large.txt")
+    end
+  end
+
+  context "when skipping errors" do
+    before do
+      source.join("a.txt").write("a")
+      File.symlink("missing.txt", source.join("broken.txt"))
+    end
+
+    it "moves the remaining files and exits with an error" do
+      output = archive_photo("-x", "txt", "--skip-errors")
+      expect($?.exitstatus).to eq(1)
+      expect(output).to include("broken.txt", "1 file(s) could not be archived")
+      expect(archived.count).to eq(1)
+    end
+  end
+
+  [1, 2, 3].each do |depth|
+    context "when the date folder depth is #{depth}" do
+      before { source.join("a.txt").write("a") }
+
+      it "nests the file #{depth} folders deep" do
+        archive_photo("-x", "txt", "--depth=#{depth}")
+        expect(archived.first.dirname.relative_path_from(archive).each_filename.count).to eq(depth)
+      end
+    end
+  end
+
+  context "when choosing the file time" do
+    let(:preload) { Pathname.new(Dir.mktmpdir).join("birthtime.rb") }
+
+    before do
+      preload.write(<<~RUBY)
+        class Pathname
+          def birthtime
+            Time.new(2019, 7, 4, 12)
+          end
+        end
+      RUBY
+      source.join("a.txt").write("a")
+      File.utime(Time.new(2020, 12, 26, 12), Time.new(2020, 12, 26, 12), source.join("a.txt"))
+    end
+
+    after { FileUtils.rm_rf(preload.dirname) }
+
+    it "files it under the creation date by default" do
+      archive_photo("-x", "txt", env: {"RUBYOPT" => "-r#{preload}"})
+      expect(archived.first.dirname).to eq(archive.join("2019", "07", "04"))
+    end
+
+    it "files it under the modification date when asked" do
+      archive_photo("-x", "txt", "--time-source=modified", env: {"RUBYOPT" => "-r#{preload}"})
+      expect(archived.first.dirname).to eq(archive.join("2020", "12", "26"))
+    end
+  end
+
+  context "when limiting the recursion depth" do
+    before do
+      source.join("sub", "deeper").mkpath
+      source.join("a.txt").write("a")
+      source.join("sub", "b.txt").write("b")
+      source.join("sub", "deeper", "c.txt").write("c")
+    end
+
+    it "stays in the top directory at depth 0" do
+      archive_photo("-R", "-x", "txt", "--max-depth=0")
+      expect(archived.count).to eq(1)
+      expect(source.join("sub", "b.txt")).to exist
+    end
+
+    it "descends one level at depth 1" do
+      archive_photo("-R", "-x", "txt", "--max-depth=1")
+      expect(archived.count).to eq(2)
+      expect(source.join("sub", "deeper", "c.txt")).to exist
+    end
+
+    it "rejects a negative depth" do
+      expect(archive_photo("-R", "-x", "txt", "--max-depth=-1")).to include("invalid argument", "-1")
+      expect($?.exitstatus).to eq(1)
+      expect(archived).to be_empty
+      expect(source.join("a.txt")).to exist
+    end
+  end
+
+  {
+    "--depth" => "5",
+    "--min-size" => "10X",
+    "--max-size" => "2Q",
+    "--time-source" => "accessed",
+    "--min-year" => "last",
+    "--after" => "garbage",
+    "--before" => "garbage",
+    "--retries" => "-1"
+  }.each do |option, value|
+    context "when #{option} is #{value}" do
+      before { source.join("a.txt").write("a") }
+
+      it "stops before moving anything" do
+        expect(archive_photo("-x", "txt", "#{option}=#{value}")).to include("invalid argument", value)
+        expect($?.exitstatus).to eq(1)
+        expect(archived).to be_empty
+        expect(source.join("a.txt")).to exist
+      end
+    end
+  end
+
+  context "when a photo only has the date it was digitized" do
+    before { FileUtils.cp("fixtures/photos/digitized-only.jpg", source) }
+
+    it "files it under the digitized date" do
+      archive_photo
+      expect(archived.first.dirname).to eq(archive.join("2020", "12", "26"))
+    end
+  end
+
+  context "when a photo's date is padded and has no time" do
+    before { FileUtils.cp("fixtures/photos/padded-date.jpg", source) }
+
+    it "files it under that date" do
+      archive_photo
+      expect(archived.first.dirname).to eq(archive.join("2019", "07", "04"))
+    end
+  end
+
+  context "when listing files" do
+    before { %w[c.txt a.txt b.txt].each { |name| source.join(name).write(name) } }
+
+    it "visits them in sorted order" do
+      analyzed = archive_photo("-i").lines.grep(/ -- /).map { |line| File.basename(line.split(" -- ").first) }
+      expect(analyzed).to eq(%w[a.txt b.txt c.txt])
+    end
+  end
+
+  context "when a photo's date is before the minimum year" do
+    before do
+      FileUtils.cp("fixtures/photos/epoch-date.jpg", source)
+      File.utime(Time.new(2020, 12, 26, 12), Time.new(2020, 12, 26, 12), source.join("epoch-date.jpg"))
+    end
+
+    it "warns and files it under the modification date" do
+      output = archive_photo("--min-year=1990", "--time-source=modified")
+      expect(output).to include("ignoring implausible EXIF date 1970-01-01")
+      expect(archived.first.dirname).to eq(archive.join("2020", "12", "26"))
+    end
+  end
+
+  context "when a photo's date is in the future" do
+    before do
+      FileUtils.cp("fixtures/photos/future-date.jpg", source)
+      File.utime(Time.new(2020, 12, 26, 12), Time.new(2020, 12, 26, 12), source.join("future-date.jpg"))
+    end
+
+    it "warns and files it under the modification date" do
+      output = archive_photo("--time-source=modified")
+      expect(output).to include("ignoring implausible EXIF date 2099-01-01")
+      expect(archived.first.dirname).to eq(archive.join("2020", "12", "26"))
+    end
+  end
+
+  context "when a photo is dated late today in a time zone ahead of UTC" do
+    let(:today) { Time.now.getlocal("+14:00") }
+
+    before do
+      photo = File.binread("fixtures/photos/epoch-date.jpg").sub("1970:01:01 00:00:00", today.strftime("%Y:%m:%d 23:59:59"))
+      source.join("today.jpg").binwrite(photo)
+      File.utime(Time.new(2020, 12, 26, 12), Time.new(2020, 12, 26, 12), source.join("today.jpg"))
+    end
+
+    it "files it under that date" do
+      output = archive_photo("--time-source=modified", env: {"TZ" => "<+14>-14"})
+      expect(output).not_to include("implausible")
+      expect(archived.first.dirname).to eq(archive.join(*today.strftime("%Y %m %d").split))
+    end
+  end
+
+  context "when pruning empty directories" do
+    before do
+      source.join("emptied").mkpath
+      source.join("kept").mkpath
+      source.join("outer", "inner").mkpath
+      source.join("already-empty").mkpath
+      source.join("emptied", "a.txt").write("a")
+      source.join("kept", "b.txt").write("b")
+      source.join("kept", "notes.md").write("notes")
+      source.join("outer", "inner", "c.txt").write("c")
+    end
+
+    it "removes only the directories the move emptied" do
+      archive_photo("-R", "-x", "txt", "--prune-empty-dirs")
+      expect(source.join("emptied")).not_to exist
+      expect(source.join("outer")).not_to exist
+      expect(source.join("kept", "notes.md")).to exist
+      expect(source.join("already-empty")).to exist
+      expect(archived.count).to eq(3)
+    end
+
+    it "leaves directories reached through a symlink alone" do
+      linked = Pathname.new(Dir.mktmpdir)
+      linked.join("sub").mkpath
+      linked.join("sub", "d.txt").write("d")
+      File.symlink(linked, source.join("link"))
+
+      archive_photo("-R", "-x", "txt", "--prune-empty-dirs")
+      expect($?.exitstatus).to eq(0)
+      expect(source.join("link")).to be_symlink
+      expect(linked.join("sub")).to be_directory
+      expect(archived.count).to eq(4)
+    ensure
+      FileUtils.rm_rf(linked)
+    end
+
+    it "leaves directories below --max-depth alone" do
+      source.join("emptied", "deeper").mkpath
+      archive_photo("-R", "--max-depth=0", "-x", "txt", "--prune-empty-dirs")
+      expect(source.join("emptied", "deeper")).to exist
+      expect(source.join("already-empty")).to exist
+    end
+  end
+
+  context "when excluding hidden files" do
+    before do
+      source.join(".thumbnails").mkpath
+      source.join("a.txt").write("a")
+      source.join(".hidden.txt").write("hidden")
+      source.join(".thumbnails", "b.txt").write("b")
+    end
+
+    it "leaves hidden files and directories alone" do
+      archive_photo("-R", "-x", "txt", "--exclude-hidden")
+      expect(archived.count).to eq(1)
+      expect(source.join(".hidden.txt")).to exist
+      expect(source.join(".thumbnails", "b.txt")).to exist
+    end
+  end
+
+  context "when filtering by modification time" do
+    before do
+      FileUtils.cp("fixtures/photos/padded-date.jpg", source)
+      FileUtils.cp("fixtures/photos/digitized-only.jpg", source)
+      File.utime(Time.new(2020, 12, 26, 12), Time.new(2020, 12, 26, 12), source.join("padded-date.jpg"))
+      File.utime(Time.new(2018, 1, 1, 12), Time.new(2018, 1, 1, 12), source.join("digitized-only.jpg"))
+    end
+
+    it "selects files by modification time regardless of their EXIF date" do
+      archive_photo("--after=2020-01-01", "--before=2021-01-01")
+      expect(archived.map(&:dirname)).to contain_exactly(archive.join("2019", "07", "04"))
+      expect(source.join("digitized-only.jpg")).to exist
+    end
+  end
+
+  context "when filtering by modification time in a local time zone" do
+    before do
+      source.join("before.txt").write("before")
+      source.join("after.txt").write("after")
+      File.utime(Time.utc(2024, 6, 1, 3, 30), Time.utc(2024, 6, 1, 3, 30), source.join("before.txt"))
+      File.utime(Time.utc(2024, 6, 1, 4, 30), Time.utc(2024, 6, 1, 4, 30), source.join("after.txt"))
+    end
+
+    it "reads the boundary in that zone" do
+      archive_photo("-x", "txt", "--after=2024-06-01", env: {"TZ" => "America/New_York"})
+      expect(archived.count).to eq(1)
+      expect(source.join("before.txt")).to exist
+      expect(source.join("after.txt")).not_to exist
+    end
+  end
+
+  context "when a file has no EXIF date and its file times are unavailable" do
+    let(:preload) { Pathname.new(Dir.mktmpdir).join("no_file_times.rb") }
+
+    before do
+      preload.write(<<~RUBY)
+        class Pathname
+          def birthtime
+            raise NotImplementedError
+          end
+
+          def mtime
+            raise Errno::EACCES
+          end
+        end
+      RUBY
+      source.join("a.jpg").write("not a photo")
+      FileUtils.cp("fixtures/photos/digitized-only.jpg", source)
+    end
+
+    after { FileUtils.rm_rf(preload.dirname) }
+
+    it "skips that file and archives the rest" do
+      output = archive_photo(env: {"RUBYOPT" => "-r#{preload}"})
+      expect(output).to include("skipping #{source.realpath.join("a.jpg")}: no EXIF date, creation time, or modification time")
+      expect(source.join("a.jpg")).to exist
+      expect(archived.map(&:dirname)).to contain_exactly(archive.join("2020", "12", "26"))
+      expect(output).to include("1 file(s) could not be archived")
+      expect($?.exitstatus).to eq(1)
+    end
+  end
+
+  context "when the source directory is read-only" do
+    let(:preload) { Pathname.new(Dir.mktmpdir).join("read_only_source.rb") }
+
+    before do
+      preload.write(<<~RUBY)
+        class Pathname
+          def writable?
+            false
+          end
+        end
+      RUBY
+      source.join("a.txt").write("a")
+    end
+
+    after { FileUtils.rm_rf(preload.dirname) }
+
+    it "skips the file before copying anything" do
+      output = archive_photo("-x", "txt", env: {"RUBYOPT" => "-r#{preload}"})
+      expect(output).to include("skipping #{source.realpath.join("a.txt")}: source directory is read-only, so the file can't be moved")
+      expect($?.exitstatus).to eq(1)
+      expect(archived).to be_empty
+      expect(source.join("a.txt")).to exist
+    end
+  end
+
+  context "when a move fails with an error" do
+    let(:preload) { Pathname.new(Dir.mktmpdir).join("failing_rename.rb") }
+
+    before do
+      preload.write(<<~'RUBY')
+        class << File
+          alias_method :original_rename, :rename
+
+          def rename(from, to)
+            @calls = (@calls || 0) + 1
+            raise Errno.const_get(ENV["RENAME_ERROR"]) if @calls <= ENV["RENAME_FAILURES"].to_i
+
+            original_rename(from, to)
+          end
+        end
+
+        at_exit { warn "rename called #{File.instance_variable_get(:@calls)} time(s)" }
+      RUBY
+      source.join("a.txt").write("a")
+    end
+
+    after { FileUtils.rm_rf(preload.dirname) }
+
+    it "retries a transient error until the move succeeds" do
+      output = archive_photo("-x", "txt", env: {"RUBYOPT" => "-r#{preload}", "RENAME_ERROR" => "EAGAIN", "RENAME_FAILURES" => "2"})
+      expect(output).to include("rename called 3 time(s)")
+      expect($?.exitstatus).to eq(0)
+      expect(archived.count).to eq(1)
+    end
+
+    it "gives up after the given number of retries" do
+      output = archive_photo("-x", "txt", "--retries=1", "--skip-errors", env: {"RUBYOPT" => "-r#{preload}", "RENAME_ERROR" => "ETIMEDOUT", "RENAME_FAILURES" => "2"})
+      expect(output).to include("rename called 2 time(s)", "1 file(s) could not be archived")
+      expect(source.join("a.txt")).to exist
+    end
+
+    it "doesn't retry a permanent error" do
+      output = archive_photo("-x", "txt", "--skip-errors", env: {"RUBYOPT" => "-r#{preload}", "RENAME_ERROR" => "ENOSPC", "RENAME_FAILURES" => "1"})
+      expect(output).to include("rename called 1 time(s)", "1 file(s) could not be archived")
+      expect(source.join("a.txt")).to exist
+    end
+  end
+
+  context "when moving to another device" do
+    let(:preload) { Pathname.new(Dir.mktmpdir).join("cross_device.rb") }
+
+    before do
+      preload.write(<<~'RUBY')
+        require "fileutils"
+
+        class << File
+          alias_method :original_rename, :rename
+
+          def rename(from, to)
+            raise Errno::EXDEV unless from.to_s.end_with?(".part")
+
+            original_rename(from, to)
+          end
+        end
+
+        if ENV["COPY_FAILS"]
+          module FileUtils
+            def self.cp(src, dest, **options)
+              File.write(dest, "partial")
+              raise Errno::EIO
+            end
+          end
+        end
+      RUBY
+      source.join("a.txt").write("a")
+    end
+
+    after { FileUtils.rm_rf(preload.dirname) }
+
+    def archived_entries
+      archive.glob("**/*", File::FNM_DOTMATCH).select(&:file?)
+    end
+
+    it "copies through a temporary file and removes the original" do
+      archive_photo("-x", "txt", env: {"RUBYOPT" => "-r#{preload}"})
+      expect(archived_entries.map(&:extname)).to eq([".txt"])
+      expect(archived_entries.first.read).to eq("a")
+      expect(source.join("a.txt")).not_to exist
+    end
+
+    it "leaves no partial file when the copy fails" do
+      output = archive_photo("-x", "txt", "--skip-errors", env: {"RUBYOPT" => "-r#{preload}", "COPY_FAILS" => "1"})
+      expect(output).to include("1 file(s) could not be archived")
+      expect(archived_entries).to be_empty
+      expect(source.join("a.txt")).to exist
+    end
+  end
+end
@@ -1,3 +1,7 @@
+require "fileutils"
+require "pathname"
+require "tmpdir"
+
 RSpec.describe "Deduplicating bags" do
   context "when there are no duplicate files" do
     subject { `ruby script/dedup-bag.rb fixtures/good-bag` }
@@ -8,18 +12,112 @@ RSpec.describe "Deduplicating bags" do
   context "when there are duplicate files in the bag" do
     subject { `ruby script/dedup-bag.rb fixtures/dup-bag`.split("\n") }
 
-    it { is_expected.to contain_exactly("delete data/c.txt", "delete data/d.txt") }
+    it { is_expected.to contain_exactly("delete data/c.txt (matches data/b.txt)", "delete data/d.txt (matches data/b.txt)") }
   end
 
   context "when specifying a remote manifest with duplicates" do
     subject { `ruby script/dedup-bag.rb -m fixtures/remote-manifest-md5.txt fixtures/good-bag`.split("\n") }
 
-    it { is_expected.to contain_exactly("delete data/b.txt") }
+    it { is_expected.to contain_exactly("delete data/b.txt (matches data/c.txt)") }
   end
 
   context "when there are both duplicates in the bag and in the remote manifest" do
     subject { `ruby script/dedup-bag.rb -m fixtures/remote-manifest-md5.txt fixtures/dup-bag`.split("\n") }
 
-    it { is_expected.to contain_exactly("delete data/b.txt", "delete data/c.txt", "delete data/d.txt") }
+    it { is_expected.to contain_exactly(
+        "delete data/b.txt (matches data/c.txt)",
+        "delete data/c.txt (matches data/c.txt)",
+        "delete data/d.txt (matches data/c.txt)"
+      ) }
+  end
+
+  context "when duplicate paths contain spaces and percent-encoded characters" do
+    subject { `ruby script/dedup-bag.rb fixtures/space-bag`.split("\n") }
+
+    it { is_expected.to contain_exactly("delete data/a copy.txt (matches data/a.txt)", "delete data/100%.txt (matches data/a.txt)") }
+  end
+
+  context "when duplicate paths contain encoded line breaks" do
+    let(:bag) { Pathname.new(Dir.mktmpdir) }
+    let(:names) { ["a.txt", "line\nbreak.txt", "carriage\rreturn.txt"] }
+
+    before do
+      bag.join("data").mkpath
+      bag.join("bagit.txt").write("BagIt-Version: 1.0\nTag-File-Character-Encoding: UTF-8\n")
+      names.each { |name| bag.join("data", name).write("a\n") }
+      bag.join("manifest-md5.txt").write(<<~MANIFEST)
+        60b725f10c9c85c70d97880dfe8191b3 data/a.txt
+        60b725f10c9c85c70d97880dfe8191b3 data/line%0Abreak.txt
+        60b725f10c9c85c70d97880dfe8191b3 data/carriage%0dreturn.txt
+      MANIFEST
+    end
+
+    after { FileUtils.rm_rf(bag) }
+
+    it "decodes the paths and removes the duplicates" do
+      `ruby script/dedup-bag.rb -x #{bag}`
+      expect(bag.join("data").children.map(&:basename).map(&:to_s)).to contain_exactly("a.txt")
+    end
+  end
+
+  context "when the remote manifest has blank lines and comments" do
+    subject { `ruby script/dedup-bag.rb -m fixtures/remote-manifest-comments-md5.txt fixtures/good-bag`.split("\n") }
+
+    it { is_expected.to contain_exactly("delete data/b.txt (matches data/c.txt)") }
+  end
+
+  context "when the remote manifest is in BSD format with uppercase checksums" do
+    subject { `ruby script/dedup-bag.rb -m fixtures/remote-manifest-bsd-md5.txt fixtures/good-bag`.split("\n") }
+
+    it { is_expected.to contain_exactly("delete data/b.txt (matches data/c.txt)") }
+  end
+
+  context "when the remote manifest has a line without a path" do
+    subject { `ruby script/dedup-bag.rb -m fixtures/remote-manifest-invalid-md5.txt fixtures/good-bag 2>&1` }
+
+    it { is_expected.to eq("fixtures/remote-manifest-invalid-md5.txt:2: expected a checksum and a path, got \"60b725f10c9c85c70d97880dfe8191b3\"\n") }
+  end
+
+  {
+    "a path with no checksum" => "data/a.txt",
+    "a checksum followed only by whitespace" => "60b725f10c9c85c70d97880dfe8191b3 \t",
+    "a checksum that isn't hex" => "60b725f10c9c85c70d97880dfe8191zz data/a.txt",
+    "its columns swapped" => "data/a.txt 60b725f10c9c85c70d97880dfe8191b3",
+    "a BSD line for another algorithm" => "SHA1 (data/a.txt) = 3f786850e387550fdab836ed7e6dc881de23001b"
+  }.each do |description, line|
+    context "when the remote manifest has #{description}" do
+      let(:manifest) { Pathname.new(Dir.mktmpdir).join("manifest-md5.txt") }
+
+      before { manifest.write("3b5d5c3712955042212316173ccf37be data/c.txt\n#{line}\n") }
+      after { FileUtils.rm_rf(manifest.dirname) }
+
+      subject { `ruby script/dedup-bag.rb -m #{manifest} fixtures/good-bag 2>&1` }
+
+      it { is_expected.to eq("#{manifest}:2: expected a checksum and a path, got #{line.inspect}\n") }
+    end
+  end
+
+  context "when the bag has no bagit.txt" do
+    subject { `ruby script/dedup-bag.rb fixtures/no-declaration-bag 2>&1` }
+
+    it { is_expected.to eq("fixtures/no-declaration-bag: missing bagit.txt\n") }
+  end
+
+  context "when the bag's bagit.txt declares an unsupported version" do
+    subject { `ruby script/dedup-bag.rb fixtures/bad-declaration-bag 2>&1` }
+
+    it { is_expected.to eq("fixtures/bad-declaration-bag/bagit.txt: unsupported BagIt-Version \"2.0\"\n") }
+  end
+
+  context "when the bag's bagit.txt declares another encoding" do
+    subject { `ruby script/dedup-bag.rb fixtures/utf-16-bag 2>&1` }
+
+    it { is_expected.to eq("fixtures/utf-16-bag/bagit.txt: Tag-File-Character-Encoding must be UTF-8, got \"UTF-16\"\n") }
+  end
+
+  context "when the bag's bagit.txt writes UTF-8 in lowercase" do
+    subject { `ruby script/dedup-bag.rb fixtures/lowercase-encoding-bag 2>&1` }
+
+    it { is_expected.to eq("delete data/b.txt (matches data/a.txt)\n") }
   end
 end